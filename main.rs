@@ -1,160 +1,694 @@
-use std::{collections::HashMap, fmt, io, io::Write};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+    io, io::Write,
+};
+
+/// The evaluation domain, so `√(-1)` and `(-8)^0.5` evaluate to an
+/// imaginary answer instead of `NaN`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn real(re: f64) -> Complex {
+        Complex { re, im: 0.0 }
+    }
+
+    fn is_real(self) -> bool {
+        self.im == 0.0
+    }
+
+    fn modulus(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    fn argument(self) -> f64 {
+        // `atan2` is sensitive to the sign of a zero imaginary part, which
+        // would otherwise put `-1` on the wrong side of the branch cut.
+        if self.im == 0.0 {
+            if self.re < 0.0 {
+                std::f64::consts::PI
+            } else {
+                0.0
+            }
+        } else {
+            self.im.atan2(self.re)
+        }
+    }
+
+    fn ln(self) -> Complex {
+        Complex {
+            re: self.modulus().ln(),
+            im: self.argument(),
+        }
+    }
+
+    fn exp(self) -> Complex {
+        let r = self.re.exp();
+        Complex {
+            re: r * self.im.cos(),
+            im: r * self.im.sin(),
+        }
+    }
+
+    /// Principal branch of `self ^ exponent`, picking exact real/imaginary
+    /// results where possible instead of picking up `exp`/`ln` round-trip
+    /// noise (e.g. `(-1)^0.5 == i`).
+    fn powc(self, exponent: Complex) -> Complex {
+        if self.re == 0.0 && self.im == 0.0 {
+            return Complex::real(0.0);
+        }
+        if self.is_real() && exponent.is_real() {
+            if self.re >= 0.0 || exponent.re.fract() == 0.0 {
+                return Complex::real(self.re.powf(exponent.re));
+            }
+            // Negative real base, fractional exponent: snap to the exact
+            // axis value when `exponent * pi` lands on one, instead of
+            // leaving float noise from `cos`/`sin` on the other axis.
+            let magnitude = (-self.re).powf(exponent.re);
+            let doubled = exponent.re * 2.0;
+            if doubled.fract() == 0.0 {
+                return match doubled.rem_euclid(4.0) as i64 {
+                    0 => Complex::real(magnitude),
+                    1 => Complex { re: 0.0, im: magnitude },
+                    2 => Complex::real(-magnitude),
+                    _ => Complex { re: 0.0, im: -magnitude },
+                };
+            }
+            let theta = exponent.re * std::f64::consts::PI;
+            return Complex {
+                re: magnitude * theta.cos(),
+                im: magnitude * theta.sin(),
+            };
+        }
+        if exponent.is_real() && exponent.re.fract() == 0.0 {
+            return self.powi(exponent.re as i64);
+        }
+        (exponent * self.ln()).exp()
+    }
+
+    /// Integer power by squaring, so e.g. `i^2 == -1` comes out exact.
+    fn powi(self, n: i64) -> Complex {
+        if n < 0 {
+            return Complex::real(1.0) / self.powi(-n);
+        }
+        let mut result = Complex::real(1.0);
+        let mut base = self;
+        let mut exp = n;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = result * base;
+            }
+            base = base * base;
+            exp >>= 1;
+        }
+        result
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, rhs: Complex) -> Complex {
+        Complex {
+            re: self.re + rhs.re,
+            im: self.im + rhs.im,
+        }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex {
+            re: self.re - rhs.re,
+            im: self.im - rhs.im,
+        }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex {
+            re: self.re * rhs.re - self.im * rhs.im,
+            im: self.re * rhs.im + self.im * rhs.re,
+        }
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, rhs: Complex) -> Complex {
+        let denom = rhs.re * rhs.re + rhs.im * rhs.im;
+        Complex {
+            re: (self.re * rhs.re + self.im * rhs.im) / denom,
+            im: (self.im * rhs.re - self.re * rhs.im) / denom,
+        }
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+    fn neg(self) -> Complex {
+        Complex {
+            re: -self.re,
+            im: -self.im,
+        }
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.im == 0.0 {
+            write!(f, "{}", self.re)
+        } else if self.re == 0.0 {
+            write!(f, "{}i", self.im)
+        } else if self.im < 0.0 {
+            write!(f, "{} - {}i", self.re, -self.im)
+        } else {
+            write!(f, "{} + {}i", self.re, self.im)
+        }
+    }
+}
+
+/// A byte range into the original input line, for pointing errors at it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    fn join(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Token {
+enum TokenKind {
     Atom(char),
     Op(char),
     Eof,
 }
 
+type Token = (TokenKind, Span);
+
+/// An error produced anywhere in the parse/eval pipeline, reported caret-style.
+#[derive(Debug)]
+struct CalcError {
+    span: Span,
+    message: String,
+}
+
+impl CalcError {
+    fn new(span: Span, message: impl Into<String>) -> CalcError {
+        CalcError {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Prints the offending line, a caret row under the bad span, and the message.
+    fn report(&self, input: &str) {
+        println!("{}", input);
+        let (start, end) = Self::caret_columns(input, self.span);
+        let marker: String = " ".repeat(start) + &"^".repeat(end - start);
+        println!("{}", marker);
+        println!("{}", self.message);
+    }
+
+    /// Converts a byte-offset `Span` to `(start, end)` terminal columns,
+    /// counting chars rather than bytes so a multi-byte char like `√`
+    /// doesn't shift the caret line out of alignment.
+    fn caret_columns(input: &str, span: Span) -> (usize, usize) {
+        let char_len = input.chars().count();
+        let byte_start = span.start.min(input.len());
+        let start = input[..byte_start].chars().count();
+        let byte_end = span.end.min(input.len());
+        let end = input[..byte_end]
+            .chars()
+            .count()
+            .max(start + 1)
+            .min(char_len + 1);
+        (start, end)
+    }
+}
+
 struct Lexer {
     tokens: Vec<Token>,
+    eof_span: Span,
 }
 
 impl Lexer {
-    fn new(mut input: String) -> Lexer {
-        let mut tokens = input
-            .chars()
-            .filter(|it| !it.is_ascii_whitespace())
-            .map(|c| match c {
-                '0'..='9' | 'a'..='z' | 'A'..='Z' => Token::Atom(c),
-                _ => Token::Op(c),
-            })
-            .collect::<Vec<_>>();
+    fn new(input: &str) -> Lexer {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut chars = input.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c.is_ascii_whitespace() {
+                continue;
+            }
+            // `==`, `!=`, `<=`, `>=` fold into a single synthetic operator char.
+            let two_char = matches!(c, '=' | '!' | '<' | '>')
+                && matches!(chars.peek(), Some((_, '=')));
+            // A `.` between two digits with no gap (e.g. `3.14`) is a decimal
+            // point, folded into the number's atom rather than left as the
+            // (otherwise unused) `.` operator.
+            let decimal_point = c == '.'
+                && matches!(tokens.last(), Some((TokenKind::Atom(p), span))
+                    if p.is_ascii_digit() && span.end == start)
+                && matches!(chars.peek(), Some((_, next)) if next.is_ascii_digit());
+            let kind = match (c, two_char, decimal_point) {
+                ('=', true, _) => TokenKind::Op('≡'),
+                ('!', true, _) => TokenKind::Op('≠'),
+                ('<', true, _) => TokenKind::Op('≤'),
+                ('>', true, _) => TokenKind::Op('≥'),
+                ('.', _, true) => TokenKind::Atom('.'),
+                ('0'..='9' | 'a'..='z' | 'A'..='Z', _, _) => TokenKind::Atom(c),
+                (op, _, _) => TokenKind::Op(op),
+            };
+            let end = if two_char {
+                let (eq_start, eq_c) = chars.next().unwrap();
+                eq_start + eq_c.len_utf8()
+            } else {
+                start + c.len_utf8()
+            };
+            tokens.push((kind, Span { start, end }));
+        }
+        let eof_span = Span {
+            start: input.len(),
+            end: input.len(),
+        };
         tokens.reverse();
-        Lexer { tokens }
+        Lexer { tokens, eof_span }
     }
 
     fn next(&mut self) -> Token {
-        self.tokens.pop().unwrap_or(Token::Eof)
+        self.tokens.pop().unwrap_or((TokenKind::Eof, self.eof_span))
     }
 
     fn peek(&mut self) -> Token {
-        self.tokens.last().copied().unwrap_or(Token::Eof)
+        self.tokens.last().copied().unwrap_or((TokenKind::Eof, self.eof_span))
     }
 }
 
+#[derive(Clone)]
 enum Expression {
-    Atom(String),
-    Operation(char, Vec<Expression>),
+    Atom(String, Span),
+    Operation(char, Vec<Expression>, Span),
+    Call(String, Vec<Expression>, Span),
+    If(Box<Expression>, Box<Expression>, Box<Expression>, Span),
+}
+
+impl Expression {
+    fn span(&self) -> Span {
+        match self {
+            Expression::Atom(_, span) => *span,
+            Expression::Operation(_, _, span) => *span,
+            Expression::Call(_, _, span) => *span,
+            Expression::If(_, _, _, span) => *span,
+        }
+    }
 }
 
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Expression::Atom(i) => write!(f, "{}", i),
-            Expression::Operation(head, rest) => {
+            Expression::Atom(i, _) => write!(f, "{}", i),
+            Expression::Operation(head, rest, _) => {
                 write!(f, "({}", head)?;
                 for s in rest {
                     write!(f, " {}", s)?
                 }
                 write!(f, ")")
             }
+            Expression::Call(name, args, _) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expression::If(cond, then_branch, else_branch, _) => {
+                write!(f, "(if {} then {} else {})", cond, then_branch, else_branch)
+            }
         }
     }
 }
 
-fn infix_binding_power(op: char) -> (f32, f32) {
+/// A function's parameter names alongside its body, keyed by name.
+type Functions = HashMap<String, (Vec<String>, Expression)>;
+
+/// What the left side of a top-level `=` binds.
+enum Assignment {
+    Variable(String, Expression),
+    Function(String, Vec<String>, Expression),
+}
+
+fn infix_binding_power(op: char) -> Option<(f32, f32)> {
+    match op {
+        '=' => Some((0.2, 0.1)),
+        // Comparisons bind tighter than `=` but looser than arithmetic, so
+        // `a = b > c` reads as `a = (b > c)`.
+        '<' | '>' | '≡' | '≠' | '≤' | '≥' => Some((0.5, 0.6)),
+        '+' | '-' => Some((1.0, 1.1)),
+        '*' | '/' => Some((2.0, 2.1)),
+        '^' | '√' => Some((3.1, 3.0)),
+        '.' => Some((4.0, 4.1)),
+        _ => None,
+    }
+}
+
+fn prefix_binding_power(op: char) -> Option<((), f32)> {
     match op {
-        '=' => (0.2, 0.1),
-        '+' | '-' => (1.0, 1.1),
-        '*' | '/' => (2.0, 2.1),
-        '^' | '√' => (3.1, 3.0),
-        '.' => (4.0, 4.1),
-        _ => panic!("bad op: {:?}", op),
-    }
-}
-
-fn parse_expression(lexer: &mut Lexer, min_bp: f32) -> Expression {
-    let mut lhs = match lexer.next() {
-        Token::Atom(it) => {
-            let mut atom: String = Default::default();
-            atom.push(it);
-            loop {
-                match lexer.peek() {
-                    Token::Atom(it) => {
-                        lexer.next();
-                        atom.push(it);
+        '+' | '-' => Some(((), 2.5)),
+        '√' => Some(((), 3.5)),
+        _ => None,
+    }
+}
+
+/// Joins consecutive touching `Atom` tokens into a single word, e.g. `then`.
+fn parse_word(lexer: &mut Lexer, first: char, first_span: Span) -> (String, Span) {
+    let mut word = String::new();
+    word.push(first);
+    let mut span = first_span;
+    loop {
+        match lexer.peek() {
+            (TokenKind::Atom(it), next_span) if next_span.start == span.end => {
+                lexer.next();
+                word.push(it);
+                span = span.join(next_span);
+            }
+            _ => break,
+        }
+    }
+    (word, span)
+}
+
+fn expect_word(lexer: &mut Lexer, expected: &str) -> Result<Span, CalcError> {
+    let (tok, tok_span) = lexer.next();
+    match tok {
+        TokenKind::Atom(it) => {
+            let (word, span) = parse_word(lexer, it, tok_span);
+            if word == expected {
+                Ok(span)
+            } else {
+                Err(CalcError::new(
+                    span,
+                    format!("expected `{}`, found `{}`", expected, word),
+                ))
+            }
+        }
+        _ => Err(CalcError::new(tok_span, format!("expected `{}`", expected))),
+    }
+}
+
+fn parse_expression(lexer: &mut Lexer, min_bp: f32) -> Result<Expression, CalcError> {
+    let (tok, tok_span) = lexer.next();
+    let mut lhs = match tok {
+        TokenKind::Atom(it) => {
+            let (atom, span) = parse_word(lexer, it, tok_span);
+            if atom == "if" {
+                let cond = parse_expression(lexer, 0.0)?;
+                expect_word(lexer, "then")?;
+                let then_branch = parse_expression(lexer, 0.0)?;
+                expect_word(lexer, "else")?;
+                let else_branch = parse_expression(lexer, 0.0)?;
+                let span = span.join(else_branch.span());
+                Expression::If(
+                    Box::new(cond),
+                    Box::new(then_branch),
+                    Box::new(else_branch),
+                    span,
+                )
+            } else if let (TokenKind::Op('('), _) = lexer.peek() {
+                lexer.next();
+                let mut args = Vec::new();
+                if !matches!(lexer.peek(), (TokenKind::Op(')'), _)) {
+                    loop {
+                        args.push(parse_expression(lexer, 0.0)?);
+                        match lexer.peek() {
+                            (TokenKind::Op(','), _) => {
+                                lexer.next();
+                            }
+                            _ => break,
+                        }
                     }
-                    Token::Op(..) => break,
-                    Token::Eof => break,
                 }
+                let (close, close_span) = lexer.next();
+                if close != TokenKind::Op(')') {
+                    return Err(CalcError::new(close_span, "expected `)`"));
+                }
+                Expression::Call(atom, args, span.join(close_span))
+            } else {
+                Expression::Atom(atom, span)
             }
-            Expression::Atom(atom)
         }
-        Token::Op('(') => {
-            let lhs = parse_expression(lexer, 0.0);
-            assert_eq!(lexer.next(), Token::Op(')'));
+        TokenKind::Op('(') => {
+            let lhs = parse_expression(lexer, 0.0)?;
+            let (close, close_span) = lexer.next();
+            if close != TokenKind::Op(')') {
+                return Err(CalcError::new(close_span, "expected `)`"));
+            }
             lhs
         }
-        t => panic!("bad token: {:?}", t),
+        TokenKind::Eof => return Err(CalcError::new(tok_span, "unexpected end of input")),
+        TokenKind::Op(op) if prefix_binding_power(op).is_some() => {
+            let (_, r_bp) = prefix_binding_power(op).unwrap();
+            let operand = parse_expression(lexer, r_bp)?;
+            let span = tok_span.join(operand.span());
+            Expression::Operation(op, vec![operand], span)
+        }
+        TokenKind::Op(op) => {
+            return Err(CalcError::new(tok_span, format!("unexpected `{}`", op)))
+        }
     };
     loop {
-        let op = match lexer.peek() {
-            Token::Eof => break,
-            Token::Op(')') => break,
-            Token::Op(op) => op,
-            t => panic!("bad token: {:?}", t),
+        let (op, op_span) = match lexer.peek() {
+            (TokenKind::Eof, _) => break,
+            (TokenKind::Op(')'), _) => break,
+            (TokenKind::Op(','), _) => break,
+            (TokenKind::Op(op), span) => (op, span),
+            // A bare word (e.g. `then`/`else`) isn't an infix operator.
+            (TokenKind::Atom(..), _) => break,
+        };
+        let (l_bp, r_bp) = match infix_binding_power(op) {
+            Some(bp) => bp,
+            None => return Err(CalcError::new(op_span, format!("unknown operator `{}`", op))),
         };
-        let (l_bp, r_bp) = infix_binding_power(op);
         if l_bp < min_bp {
             break;
         }
         lexer.next();
-        let rhs = parse_expression(lexer, r_bp);
-        lhs = Expression::Operation(op, vec![lhs, rhs]);
+        let rhs = parse_expression(lexer, r_bp)?;
+        let span = lhs.span().join(rhs.span());
+        lhs = Expression::Operation(op, vec![lhs, rhs], span);
     }
-    lhs
+    Ok(lhs)
 }
 
 impl Expression {
-    fn from_str(input: String) -> Expression {
+    fn from_str(input: &str) -> Result<Expression, CalcError> {
         let mut lexer = Lexer::new(input);
-        parse_expression(&mut lexer, 0.0)
+        let expr = parse_expression(&mut lexer, 0.0)?;
+        let (tok, span) = lexer.next();
+        let span = match tok {
+            TokenKind::Eof => return Ok(expr),
+            TokenKind::Atom(it) => parse_word(&mut lexer, it, span).1,
+            _ => span,
+        };
+        Err(CalcError::new(span, "unexpected trailing input"))
     }
     #[allow(unused)]
-    fn is_asign(&self) -> Option<(String, &Expression)> {
+    fn as_assignment(&self) -> Option<Assignment> {
         match self {
-            Expression::Atom(_) => return None,
-            Expression::Operation(c, operands) => {
-                if *c == '=' {
-                    let var_name = match operands.first().unwrap() {
-                        Expression::Atom(c) => c.clone(),
-                        _ => unreachable!(),
-                    };
-                    return Some((var_name, operands.last().unwrap()));
+            Expression::Operation('=', operands, _) => {
+                let lhs = operands.first().unwrap();
+                let rhs = operands.last().unwrap();
+                match lhs {
+                    Expression::Atom(name, _) => Some(Assignment::Variable(name.clone(), rhs.clone())),
+                    Expression::Call(name, args, _) => {
+                        let mut params = Vec::with_capacity(args.len());
+                        for arg in args {
+                            match arg {
+                                Expression::Atom(param, _) => params.push(param.clone()),
+                                _ => return None,
+                            }
+                        }
+                        Some(Assignment::Function(name.clone(), params, rhs.clone()))
+                    }
+                    _ => None,
                 }
-                return None;
             }
+            _ => None,
         }
     }
     #[allow(unused)]
-    fn eval(&self, variables: &HashMap<String, f32>) -> f32 {
+    fn eval(&self, variables: &HashMap<String, Complex>, functions: &Functions) -> Result<Complex, CalcError> {
         match self {
-            Expression::Atom(c) => match c.parse::<f32>() {
-                Ok(num) => num,
-                Err(e) => *variables
-                    .get(c)
-                    .expect(&format!("Undefined variable {}", c)),
-            },
-            Expression::Operation(operator, operands) => {
-                let lhs = operands.first().unwrap().eval(variables);
-                let rhs = operands.last().unwrap().eval(variables);
+            Expression::Atom(c, span) => {
+                if let Some(value) = variables.get(c) {
+                    return Ok(*value);
+                }
+                if c == "i" {
+                    return Ok(Complex { re: 0.0, im: 1.0 });
+                }
+                if let Some(im) = c.strip_suffix('i').and_then(|prefix| prefix.parse::<f64>().ok()) {
+                    return Ok(Complex { re: 0.0, im });
+                }
+                match c.parse::<f64>() {
+                    Ok(re) => Ok(Complex::real(re)),
+                    Err(_) => Err(CalcError::new(*span, format!("undefined variable `{}`", c))),
+                }
+            }
+            Expression::Operation(operator, operands, span) if operands.len() == 1 => {
+                let val = operands[0].eval(variables, functions)?;
+                match operator {
+                    '-' => Ok(-val),
+                    '+' => Ok(val),
+                    '√' => Ok(val.powc(Complex::real(0.5))),
+                    op => Err(CalcError::new(*span, format!("bad unary operator `{}`", op))),
+                }
+            }
+            Expression::Operation(operator, operands, span) => {
+                let lhs = operands.first().unwrap().eval(variables, functions)?;
+                let rhs = operands.last().unwrap().eval(variables, functions)?;
                 match operator {
-                    '+' => return lhs + rhs,
-                    '-' => return lhs - rhs,
-                    '*' => return lhs * rhs,
-                    '/' => return lhs / rhs,
-                    '^' => return lhs.powf(rhs),
-                    '√' => return lhs.powf(1.0 / (rhs)),
-                    op => panic!("Bad operator: {}", op),
+                    '+' => Ok(lhs + rhs),
+                    '-' => Ok(lhs - rhs),
+                    '*' => Ok(lhs * rhs),
+                    '/' => Ok(lhs / rhs),
+                    '^' => Ok(lhs.powc(rhs)),
+                    '√' => Ok(lhs.powc(Complex::real(1.0) / rhs)),
+                    // Comparisons evaluate to 0.0/1.0; ordering is only
+                    // defined for real operands.
+                    '<' | '>' | '≤' | '≥' if !lhs.is_real() || !rhs.is_real() => Err(
+                        CalcError::new(*span, "ordering is undefined for complex numbers"),
+                    ),
+                    '<' => Ok(Complex::real((lhs.re < rhs.re) as u8 as f64)),
+                    '>' => Ok(Complex::real((lhs.re > rhs.re) as u8 as f64)),
+                    '≤' => Ok(Complex::real((lhs.re <= rhs.re) as u8 as f64)),
+                    '≥' => Ok(Complex::real((lhs.re >= rhs.re) as u8 as f64)),
+                    '≡' => Ok(Complex::real((lhs == rhs) as u8 as f64)),
+                    '≠' => Ok(Complex::real((lhs != rhs) as u8 as f64)),
+                    op => Err(CalcError::new(*span, format!("bad operator `{}`", op))),
+                }
+            }
+            Expression::Call(name, args, span) => {
+                if let Some((params, body)) = functions.get(name) {
+                    if params.len() != args.len() {
+                        return Err(CalcError::new(
+                            *span,
+                            format!(
+                                "`{}` expects {} argument(s), got {}",
+                                name,
+                                params.len(),
+                                args.len()
+                            ),
+                        ));
+                    }
+                    let mut scope = variables.clone();
+                    for (param, arg) in params.iter().zip(args) {
+                        let value = arg.eval(variables, functions)?;
+                        scope.insert(param.clone(), value);
+                    }
+                    return body.eval(&scope, functions);
+                }
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(arg.eval(variables, functions)?);
+                }
+                match call_builtin(name, &values) {
+                    Some(Ok(value)) => Ok(value),
+                    Some(Err(message)) => {
+                        Err(CalcError::new(*span, format!("`{}` {}", name, message)))
+                    }
+                    None => Err(CalcError::new(*span, format!("undefined function `{}`", name))),
+                }
+            }
+            Expression::If(cond, then_branch, else_branch, _) => {
+                let cond = cond.eval(variables, functions)?;
+                if cond.re != 0.0 || cond.im != 0.0 {
+                    then_branch.eval(variables, functions)
+                } else {
+                    else_branch.eval(variables, functions)
                 }
             }
         }
     }
 }
 
+type Builtin = fn(&[Complex]) -> Result<Complex, String>;
+
+/// One-argument built-ins that are only defined over the reals (trig, `ln`,
+/// rounding); they reject operands with a nonzero imaginary part.
+/// `real_binary` does the same for two-argument ones (min/max).
+fn real_unary(args: &[Complex], f: fn(f64) -> f64) -> Result<Complex, String> {
+    match args {
+        [x] if x.is_real() => Ok(Complex::real(f(x.re))),
+        [_] => Err("is not defined for complex input".to_string()),
+        _ => Err(format!("expects 1 argument, got {}", args.len())),
+    }
+}
+
+fn real_binary(args: &[Complex], f: fn(f64, f64) -> f64) -> Result<Complex, String> {
+    match args {
+        [x, y] if x.is_real() && y.is_real() => Ok(Complex::real(f(x.re, y.re))),
+        [_, _] => Err("is not defined for complex input".to_string()),
+        _ => Err(format!("expects 2 arguments, got {}", args.len())),
+    }
+}
+
+fn complex_sqrt(args: &[Complex]) -> Result<Complex, String> {
+    match args {
+        [x] => Ok(x.powc(Complex::real(0.5))),
+        _ => Err(format!("expects 1 argument, got {}", args.len())),
+    }
+}
+
+fn complex_abs(args: &[Complex]) -> Result<Complex, String> {
+    match args {
+        [x] => Ok(Complex::real(x.modulus())),
+        _ => Err(format!("expects 1 argument, got {}", args.len())),
+    }
+}
+
+/// Static dispatch table for built-in math functions, resolved after a
+/// call's name is checked against user-defined functions.
+const BUILTINS: &[(&str, Builtin)] = &[
+    ("sin", |a| real_unary(a, f64::sin)),
+    ("cos", |a| real_unary(a, f64::cos)),
+    ("tan", |a| real_unary(a, f64::tan)),
+    ("ln", |a| real_unary(a, f64::ln)),
+    ("sqrt", complex_sqrt),
+    ("abs", complex_abs),
+    ("floor", |a| real_unary(a, f64::floor)),
+    ("ceil", |a| real_unary(a, f64::ceil)),
+    ("min", |a| real_binary(a, f64::min)),
+    ("max", |a| real_binary(a, f64::max)),
+];
+
+fn call_builtin(name: &str, args: &[Complex]) -> Option<Result<Complex, String>> {
+    BUILTINS
+        .iter()
+        .find(|(builtin_name, _)| *builtin_name == name)
+        .map(|(_, f)| f(args))
+}
+
 fn main() {
-    let mut variables: HashMap<String, f32> = HashMap::new();
+    let mut variables: HashMap<String, Complex> = HashMap::new();
+    variables.insert("pi".to_string(), Complex::real(std::f64::consts::PI));
+    variables.insert("e".to_string(), Complex::real(std::f64::consts::E));
+    let mut functions: Functions = HashMap::new();
     loop {
         print!(">> ");
         io::stdout().flush().unwrap();
@@ -166,13 +700,173 @@ fn main() {
         if input.trim() == "exit" {
             break;
         }
-        let expr = Expression::from_str(input);
-        if let Some((var_name, lhs)) = expr.is_asign() {
-            let value = lhs.eval(&variables);
-            variables.insert(var_name, value);
-            continue;
+        let expr = match Expression::from_str(input.trim_end()) {
+            Ok(expr) => expr,
+            Err(err) => {
+                err.report(input.trim_end());
+                continue;
+            }
+        };
+        match expr.as_assignment() {
+            Some(Assignment::Variable(name, rhs)) => {
+                match rhs.eval(&variables, &functions) {
+                    Ok(value) => {
+                        variables.insert(name, value);
+                    }
+                    Err(err) => err.report(input.trim_end()),
+                }
+                continue;
+            }
+            Some(Assignment::Function(name, params, body)) => {
+                functions.insert(name, (params, body));
+                continue;
+            }
+            None => {}
+        }
+        match expr.eval(&variables, &functions) {
+            Ok(value) => println!("{}", value),
+            Err(err) => err.report(input.trim_end()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(input: &str) -> Complex {
+        Expression::from_str(input)
+            .unwrap()
+            .eval(&HashMap::new(), &Functions::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn user_defined_function_is_callable() {
+        let mut functions = Functions::new();
+        match Expression::from_str("f(x) = x^2 + 1").unwrap().as_assignment() {
+            Some(Assignment::Function(name, params, body)) => {
+                functions.insert(name, (params, body));
+            }
+            _ => panic!("expected a function assignment"),
+        }
+        let call = Expression::from_str("f(3)").unwrap();
+        assert_eq!(
+            call.eval(&HashMap::new(), &functions).unwrap(),
+            Complex::real(10.0)
+        );
+    }
+
+    #[test]
+    fn unary_prefix_operators_parse_and_evaluate() {
+        assert_eq!(eval("-3"), Complex::real(-3.0));
+        assert_eq!(eval("+3"), Complex::real(3.0));
+        assert_eq!(eval("√4"), Complex::real(2.0));
+    }
+
+    #[test]
+    fn builtin_dispatch_table_covers_core_functions() {
+        assert_eq!(eval("sqrt(4)"), Complex::real(2.0));
+        assert_eq!(eval("max(2, 5)"), Complex::real(5.0));
+        assert_eq!(eval("floor(3.7)"), Complex::real(3.0));
+    }
+
+    #[test]
+    fn pi_and_e_constants_resolve_to_their_values() {
+        let mut variables = HashMap::new();
+        variables.insert("pi".to_string(), Complex::real(std::f64::consts::PI));
+        variables.insert("e".to_string(), Complex::real(std::f64::consts::E));
+        let expr = Expression::from_str("pi").unwrap();
+        assert_eq!(
+            expr.eval(&variables, &Functions::new()).unwrap(),
+            Complex::real(std::f64::consts::PI)
+        );
+        let expr = Expression::from_str("e").unwrap();
+        assert_eq!(
+            expr.eval(&variables, &Functions::new()).unwrap(),
+            Complex::real(std::f64::consts::E)
+        );
+    }
+
+    #[test]
+    fn if_then_else_does_not_eagerly_evaluate_the_untaken_branch() {
+        assert_eq!(eval("if 1==1 then 5 else undefinedVar"), Complex::real(5.0));
+        assert_eq!(eval("if 1==0 then undefinedVar else 5"), Complex::real(5.0));
+    }
+
+    #[test]
+    fn integer_powers_of_a_complex_base_are_exact() {
+        assert_eq!(eval("i^2"), Complex::real(-1.0));
+        assert_eq!(eval("i^3"), Complex { re: 0.0, im: -1.0 });
+        assert_eq!(eval("i^4"), Complex::real(1.0));
+        assert_eq!(eval("(1+1i)^2"), Complex { re: 0.0, im: 2.0 });
+    }
+
+    #[test]
+    fn a_variable_or_parameter_named_i_shadows_the_imaginary_unit() {
+        let mut variables = HashMap::new();
+        variables.insert("i".to_string(), Complex::real(5.0));
+        let expr = Expression::from_str("i").unwrap();
+        assert_eq!(
+            expr.eval(&variables, &Functions::new()).unwrap(),
+            Complex::real(5.0)
+        );
+
+        let mut functions = Functions::new();
+        match Expression::from_str("f(i) = i + 1").unwrap().as_assignment() {
+            Some(Assignment::Function(name, params, body)) => {
+                functions.insert(name, (params, body));
+            }
+            _ => panic!("expected a function assignment"),
         }
-        let value = expr.eval(&variables);
-        println!("{}", value);
+        let call = Expression::from_str("f(5)").unwrap();
+        assert_eq!(
+            call.eval(&HashMap::new(), &functions).unwrap(),
+            Complex::real(6.0)
+        );
+    }
+
+    #[test]
+    fn caret_columns_count_chars_not_bytes() {
+        // `√` is 3 bytes but a single column; the span for `xyz` in `√xyz`
+        // should start at column 1, not byte offset 3.
+        let input = "√xyz";
+        let span = Span {
+            start: "√".len(),
+            end: "√xyz".len(),
+        };
+        assert_eq!(CalcError::caret_columns(input, span), (1, 4));
+    }
+
+    #[test]
+    fn trailing_input_error_spans_the_whole_word() {
+        let err = match Expression::from_str("1+2 foobar") {
+            Err(err) => err,
+            Ok(_) => panic!("expected a trailing-input error"),
+        };
+        assert_eq!(
+            err.span,
+            Span {
+                start: "1+2 ".len(),
+                end: "1+2 foobar".len(),
+            }
+        );
+    }
+
+    #[test]
+    fn lexer_spans_are_byte_offsets_into_the_source() {
+        let mut lexer = Lexer::new("√x");
+        let (kind, span) = lexer.next();
+        assert_eq!(kind, TokenKind::Op('√'));
+        assert_eq!(span, Span { start: 0, end: "√".len() });
+        let (kind, span) = lexer.next();
+        assert_eq!(kind, TokenKind::Atom('x'));
+        assert_eq!(
+            span,
+            Span {
+                start: "√".len(),
+                end: "√".len() + 1,
+            }
+        );
     }
 }